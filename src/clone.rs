@@ -1,7 +1,18 @@
-use clap::Args;
-use std::collections::HashSet;
+use crate::url::canonical_url;
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Args, ValueEnum};
+use futures::{StreamExt, TryStreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::io::{self, BufRead, Write};
-use std::{fs, path::PathBuf};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Args, Debug)]
 pub struct CloneParams {
@@ -10,9 +21,43 @@ pub struct CloneParams {
     platform: crate::Platform,
 
     /// Compare repository list with a given file, and only clone the ones that are not in the list
+    /// (may be gzip or zstd compressed)
     #[arg(short, long)]
     compare_file: Option<PathBuf>,
 
+    /// GitHub token, raises the rate limit and gives access to private repositories
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// GitHub Enterprise API url, e.g. https://github.example.com/api/v3
+    #[arg(long)]
+    base_url: Option<reqwest::Url>,
+
+    /// Only clone repositories with at least this many stars
+    #[arg(long, default_value = "0")]
+    min_stars: u32,
+
+    /// Skip repositories that weren't pushed to since this date (YYYY-MM-DD), including ones whose
+    /// push date is unknown
+    #[arg(long)]
+    pushed_after: Option<NaiveDate>,
+
+    /// Filter out archived repositories
+    #[arg(long)]
+    skip_archived: bool,
+
+    /// Only clone repositories whose primary language matches (case-insensitive)
+    #[arg(long)]
+    language: Option<String>,
+
+    /// Only clone repositories whose name matches this regex
+    #[arg(long)]
+    include: Option<Regex>,
+
+    /// Filter out repositories whose name matches this regex
+    #[arg(long)]
+    exclude: Option<Regex>,
+
     /// Filter out forks
     #[arg(long, group = "forks")]
     filter_forks: bool,
@@ -21,51 +66,216 @@ pub struct CloneParams {
     #[arg(long, group = "forks")]
     only_forks: bool,
 
-    /// Include submodules
-    #[arg(long)]
+    /// Filter out repositories that are mirrors of another repository
+    #[arg(long, group = "mirrors")]
+    exclude_mirrors: bool,
+
+    /// Only clone repositories that are mirrors of another repository
+    #[arg(long, group = "mirrors")]
+    only_mirrors: bool,
+
+    /// Include the submodules of each repository in the output file
+    #[arg(long, requires = "output_file")]
     include_submodules: bool,
 
     /// Output repository list to a file instead of cloning
     #[arg(short, long)]
     output_file: Option<PathBuf>,
 
-    /// Prepand something to each repository in the output file
-    #[arg(
-        short,
-        long,
-        default_value = "git clone --mirror",
-        requires = "output_file"
-    )]
-    prepand_command: String,
+    /// Directory to clone into, each repository goes into a directory named after it
+    #[arg(long, default_value = ".", conflicts_with = "output_file")]
+    dest: PathBuf,
+
+    /// Clone bare mirrors (into `<name>.git`, like `git clone --mirror`)
+    #[arg(long, conflicts_with = "output_file")]
+    mirror: bool,
+
+    /// Run `git fsck` on each freshly cloned repository and report the ones that fail, it reads
+    /// every object so it's slow on large repositories
+    #[arg(long, conflicts_with = "output_file")]
+    fsck: bool,
+
+    /// Compare the repositories in --dest with the listing instead of cloning, and report which
+    /// ones are missing and which ones are no longer listed
+    #[arg(long, conflicts_with_all = ["output_file", "compare_file", "limit", "max"])]
+    prune: bool,
+
+    /// Clone the missing repositories and remove the unlisted ones after asking for confirmation,
+    /// repositories with uncommitted changes are never removed
+    #[arg(long, requires = "prune")]
+    apply: bool,
+
+    /// Format of the output file
+    #[arg(long, value_enum, default_value = "shell", requires = "output_file")]
+    format: Format,
+
+    /// Prepand something to each repository in the output file, shell format only
+    /// [default: git clone --mirror]
+    #[arg(short, long, requires = "output_file")]
+    prepand_command: Option<String>,
+
+    /// Stop after collecting this many matching repositories, meant for quick testing since which
+    /// ones come first is arbitrary
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Fetch at most this many repositories from GitHub, stopping pagination early. Unlike
+    /// --limit this applies to the raw listing, before any of the filters
+    #[arg(long)]
+    max: Option<usize>,
+
+    /// How many times to retry a failed page request
+    #[arg(long, default_value = "3")]
+    retries: u32,
+
+    /// How many github pages to fetch at once, keep it low to avoid the secondary rate limit
+    #[arg(long, default_value = "8")]
+    concurrency: NonZeroUsize,
+
+    /// Use ssh clone urls (git@host:owner/repo.git) instead of https
+    #[arg(long)]
+    ssh: bool,
+
+    /// Split the repository list into one file per group, treating the output file as a directory
+    #[arg(long, value_enum, requires = "output_file")]
+    split_by: Option<SplitBy>,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct GenericParams {
+    /// Url listing the repositories, `{page}` is replaced with the page number (starting at 1),
+    /// otherwise pages are followed through the `Link` header
+    list_url_template: String,
+
+    /// Field holding the array of repositories, the response itself is used when absent
+    #[arg(long)]
+    items_field: Option<String>,
+
+    /// Field holding the clone url
+    #[arg(long, default_value = "clone_url")]
+    clone_url_field: String,
+
+    /// Field marking a fork, either a boolean or an object that is only present for forks
+    #[arg(long)]
+    fork_field: Option<String>,
+
+    /// Field holding the source url of repositories that are mirrors
+    #[arg(long)]
+    mirror_url_field: Option<String>,
+
+    /// Field holding the owner name, taken from the clone url when absent
+    #[arg(long)]
+    owner_field: Option<String>,
+
+    /// Field of the response holding the next page url, instead of `{page}` or the `Link` header
+    #[arg(long)]
+    next_field: Option<String>,
+
+    /// Token sent as a bearer authorization header
+    #[arg(long, env = "FORGE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum Format {
+    /// A command per line, see --prepand-command
+    Shell,
+    /// An array of repository objects with their metadata
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum SplitBy {
+    Owner,
+}
+
+const GITHUB_PER_PAGE: usize = 100;
+const DEFAULT_PREPAND_COMMAND: &str = "git clone --mirror";
+
+#[derive(Serialize)]
 struct Entry {
     clone_url: String,
     is_fork: bool,
+    owner: String,
+    name: String,
+    stars: u32,
+    archived: bool,
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pushed_at: Option<DateTime<Utc>>,
+    /// Source url when the repository is itself a mirror
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mirror_url: Option<String>,
 }
 
 pub fn clone(params: CloneParams) -> anyhow::Result<()> {
-    if params.include_submodules {
-        unimplemented!("submodules are not yet supported");
+    if params.prepand_command.is_some() && matches!(params.format, Format::Json) {
+        anyhow::bail!("--prepand-command can't be used with --format json");
     }
 
-    let compare = if let Some(compare_file) = params.compare_file {
-        HashSet::from_iter(
-            io::BufReader::new(fs::File::open(compare_file)?)
-                .lines()
-                .collect::<io::Result<Vec<String>>>()?,
-        )
+    let compare = if let Some(compare_file) = &params.compare_file {
+        crate::compression::open(compare_file)?
+            .lines()
+            .map(|l| l.map(|l| canonical_url(&l)))
+            .collect::<io::Result<HashSet<String>>>()?
     } else {
         HashSet::new()
     };
 
-    let repos = match params.platform {
-        crate::Platform::Github { group_type, input } => {
-            let runtime = tokio::runtime::Runtime::new()?;
-            runtime.block_on(github(group_type, &input))?
+    let runtime = tokio::runtime::Runtime::new()?;
+    let _guard = runtime.enter();
+    let octocrab = github_client(params.token.as_deref(), params.base_url.as_ref())?;
+
+    let mut archived_count = 0;
+
+    let repos = match &params.platform {
+        crate::Platform::Github { group_type, input } => runtime.block_on(github(
+            &octocrab,
+            group_type,
+            input,
+            params.max,
+            params.retries,
+            params.concurrency,
+        ))?,
+        crate::Platform::Gitlab {
+            group_type,
+            input,
+            host,
+            token,
+        } => runtime.block_on(gitlab(
+            host,
+            token.as_deref(),
+            group_type,
+            input,
+            params.retries,
+        ))?,
+        crate::Platform::Generic(generic_params) => {
+            runtime.block_on(generic(generic_params, params.retries))?
         }
     }
     .into_iter()
+    .filter(|e| params.include.as_ref().is_none_or(|r| r.is_match(&e.name)))
+    .filter(|e| !params.exclude.as_ref().is_some_and(|r| r.is_match(&e.name)))
+    .filter(|e| e.stars >= params.min_stars)
+    .filter(|e| match &params.language {
+        Some(language) => e
+            .language
+            .as_ref()
+            .is_some_and(|l| l.eq_ignore_ascii_case(language)),
+        None => true,
+    })
+    .filter(|e| match params.pushed_after {
+        Some(cutoff) => e.pushed_at.is_some_and(|p| p.date_naive() >= cutoff),
+        None => true,
+    })
+    .filter(|e| {
+        if params.skip_archived && e.archived {
+            archived_count += 1;
+            false
+        } else {
+            true
+        }
+    })
     .filter(|e| {
         if params.filter_forks {
             !e.is_fork
@@ -75,11 +285,20 @@ pub fn clone(params: CloneParams) -> anyhow::Result<()> {
             true
         }
     })
+    .filter(|e| {
+        if params.exclude_mirrors {
+            e.mirror_url.is_none()
+        } else if params.only_mirrors {
+            e.mirror_url.is_some()
+        } else {
+            true
+        }
+    })
     .collect::<Vec<Entry>>();
 
     let total_repo_count = repos.len();
 
-    let repos = repos
+    let mut repos = repos
         .into_iter()
         .filter(|e| !is_in_compare_list(&e.clone_url, &compare))
         .collect::<Vec<Entry>>();
@@ -89,91 +308,988 @@ pub fn clone(params: CloneParams) -> anyhow::Result<()> {
         total_repo_count - repos.len()
     );
 
-    if let Some(output) = &params.output_file {
-        let mut output = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(output)?,
+    if params.skip_archived {
+        log::info!("skipped {archived_count} archived repositories");
+    }
+
+    if let Some(limit) = params.limit.filter(|l| *l < repos.len()) {
+        log::info!(
+            "limiting to the first {limit} of {} repositories",
+            repos.len()
         );
+        repos.truncate(limit);
+    }
+
+    if params.include_submodules {
+        let submodules = match &params.platform {
+            crate::Platform::Github { .. } => {
+                runtime.block_on(github_submodules(&octocrab, &repos))?
+            }
+            _ => {
+                log::warn!("submodules are only supported for the github platform, skipping...");
+                vec![]
+            }
+        };
+
+        let known = repos
+            .iter()
+            .map(|r| canonical_url(&r.clone_url))
+            .collect::<HashSet<String>>();
+        let mut added = HashSet::new();
+
+        let submodules = submodules
+            .into_iter()
+            .filter(|s| {
+                !is_in_compare_list(&s.clone_url, &known)
+                    && !is_in_compare_list(&s.clone_url, &compare)
+                    && added.insert(canonical_url(&s.clone_url))
+            })
+            .collect::<Vec<Entry>>();
+
+        log::info!("adding {} submodules", submodules.len());
+        repos.extend(submodules);
+    }
+
+    if params.ssh {
+        for r in repos
+            .iter_mut()
+            .filter(|r| !r.clone_url.starts_with("git@") && !r.clone_url.starts_with("ssh://"))
+        {
+            match ssh_url(&r.clone_url) {
+                Some(url) => r.clone_url = url,
+                None => log::warn!("'{}': not an https url, leaving it as is", r.clone_url),
+            }
+        }
+    }
+
+    if let Some(output) = &params.output_file {
+        match params.split_by {
+            Some(SplitBy::Owner) => {
+                fs::create_dir_all(output)?;
+
+                let mut groups: BTreeMap<&str, Vec<&Entry>> = BTreeMap::new();
+
+                for r in &repos {
+                    groups.entry(&r.owner).or_default().push(r);
+                }
 
-        for r in repos {
-            writeln!(output, "{} {}", params.prepand_command, r.clone_url)?;
+                let stems = file_stems(groups.keys().copied());
+
+                for (owner, repos) in groups {
+                    let extension = match params.format {
+                        Format::Shell => "txt",
+                        Format::Json => "json",
+                    };
+                    let path = output.join(format!("{}.{extension}", stems[owner]));
+                    log::info!(
+                        "writing {} repositories to '{}'",
+                        repos.len(),
+                        path.display()
+                    );
+                    write_output(&path, &repos, &params)?;
+                }
+            }
+            None => write_output(output, &repos.iter().collect::<Vec<&Entry>>(), &params)?,
+        }
+    } else {
+        let token = match &params.platform {
+            crate::Platform::Github { .. } => {
+                params.token.as_deref().map(|t| ("x-access-token", t))
+            }
+            crate::Platform::Gitlab { token, .. } => token.as_deref().map(|t| ("oauth2", t)),
+            crate::Platform::Generic(generic_params) => generic_params
+                .token
+                .as_deref()
+                .map(|t| ("x-access-token", t)),
+        };
+
+        if params.prune {
+            prune(
+                repos,
+                &params.dest,
+                params.mirror,
+                token,
+                params.fsck,
+                params.apply,
+            )?;
+        } else {
+            clone_all(&repos, &params.dest, params.mirror, token, params.fsck)?;
         }
     }
 
     Ok(())
 }
 
-async fn github(group_type: crate::RepositoryGroupType, name: &str) -> anyhow::Result<Vec<Entry>> {
-    let octocrab = octocrab::instance();
-
-    Ok(match group_type {
-        crate::RepositoryGroupType::Org => {
-            log::info!("fetching page 1...");
-            let page = octocrab
-                .orgs(name)
-                .list_repos()
-                .per_page(100)
-                .send()
-                .await?;
-
-            let pages = page.number_of_pages().unwrap_or(1);
-            log::info!("total pages: {pages}");
-            let mut current_page = 1;
-
-            let mut repos = page.items;
-
-            while current_page < pages {
-                current_page += 1;
-                log::info!("fetching page {}...", current_page);
-                repos.append(
-                    &mut octocrab
-                        .orgs(name)
-                        .list_repos()
-                        .per_page(100)
-                        .page(current_page)
-                        .send()
-                        .await?
-                        .items,
-                );
+/// `token` is the username and token used for https remotes, ssh remotes go through ssh-agent
+fn clone_all(
+    repos: &[Entry],
+    dest: &Path,
+    mirror: bool,
+    token: Option<(&str, &str)>,
+    fsck: bool,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dest)?;
+
+    let mut cloned = 0;
+    let mut existing = 0;
+    let mut errors = vec![];
+    let mut corrupt = vec![];
+    let mut fsck_time = Duration::ZERO;
+
+    for (i, r) in repos.iter().enumerate() {
+        let path = if mirror {
+            dest.join(format!("{}.git", r.name))
+        } else {
+            dest.join(&r.name)
+        };
+
+        if path.exists() {
+            log::warn!("'{}' already exists, skipping...", path.display());
+            existing += 1;
+            continue;
+        }
+
+        log::info!(
+            "cloning {} into '{}' ({}/{})...",
+            r.clone_url,
+            path.display(),
+            i + 1,
+            repos.len()
+        );
+
+        let mut attempts = 0;
+        let mut callbacks = git2::RemoteCallbacks::new();
+
+        callbacks.credentials(|_, username, allowed| {
+            // libgit2 keeps asking as long as we hand out credentials, even rejected ones
+            attempts += 1;
+            if attempts > 3 {
+                return Err(git2::Error::from_str("authentication failed"));
+            }
+
+            if allowed.contains(git2::CredentialType::USERNAME) {
+                git2::Cred::username(username.unwrap_or("git"))
+            } else if allowed.contains(git2::CredentialType::SSH_KEY) {
+                git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+            } else if let (true, Some((user, token))) = (
+                allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT),
+                token,
+            ) {
+                git2::Cred::userpass_plaintext(user, token)
+            } else {
+                git2::Cred::default()
             }
+        });
+
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+
+        let mut builder = git2::build::RepoBuilder::new();
+        builder.fetch_options(fetch_options);
 
-            repos
+        if mirror {
+            builder.bare(true).remote_create(|repo, name, url| {
+                let remote = repo.remote_with_fetch(name, url, "+refs/*:refs/*")?;
+                repo.config()?
+                    .set_bool(&format!("remote.{name}.mirror"), true)?;
+                Ok(remote)
+            });
+        }
+
+        match builder.clone(&r.clone_url, &path) {
+            Ok(_) => cloned += 1,
+            Err(e) => {
+                errors.push((r.clone_url.as_str(), e));
+                continue;
+            }
+        }
+
+        if fsck {
+            let start = Instant::now();
+            if let Err(e) = git_fsck(&path) {
+                corrupt.push((path, e));
+            }
+            fsck_time += start.elapsed();
         }
-        crate::RepositoryGroupType::User => todo!(), // octocrab.users(name).repos().send().await?,
     }
-    .into_iter()
-    .filter_map(|r| match (r.clone_url, r.fork) {
-        (Some(url), Some(fork)) => Some(Entry {
-            clone_url: url.as_str().to_owned(),
-            is_fork: fork,
-        }),
-        (u, f) => {
+
+    for (url, e) in &errors {
+        log::error!("failed to clone {url}: {e}");
+    }
+
+    log::info!(
+        "cloned {cloned} repositories, skipped {existing} existing, {} failed",
+        errors.len()
+    );
+
+    if fsck {
+        for (path, e) in &corrupt {
             log::error!(
+                "'{}' failed fsck, remove it and clone it again: {e}",
+                path.display()
+            );
+        }
+
+        log::info!(
+            "fsck passed for {} of {cloned} repositories in {fsck_time:.1?}",
+            cloned - corrupt.len()
+        );
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "failed to clone {} of {} repositories",
+            errors.len(),
+            repos.len()
+        );
+    }
+
+    if !corrupt.is_empty() {
+        anyhow::bail!(
+            "{} of {cloned} cloned repositories failed fsck",
+            corrupt.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// libgit2 has no fsck, so this needs git itself
+fn git_fsck(path: &Path) -> anyhow::Result<()> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(["fsck", "--no-progress"])
+        .output()
+        .context("failed to run git fsck")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!(
+            "{}",
+            stderr.lines().next().unwrap_or("git fsck failed").trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Reconcile `dest` with `repos`, matching on the canonical url of each repository's origin (or
+/// first) remote. Only the direct subdirectories of `dest` are considered
+fn prune(
+    repos: Vec<Entry>,
+    dest: &Path,
+    mirror: bool,
+    token: Option<(&str, &str)>,
+    fsck: bool,
+    apply: bool,
+) -> anyhow::Result<()> {
+    let mut actual = BTreeMap::new();
+
+    if dest.exists() {
+        for d in fs::read_dir(dest)? {
+            let path = d?.path();
+
+            if !path.is_dir() {
+                continue;
+            }
+
+            let repo = match git2::Repository::open(&path) {
+                Ok(repo) => repo,
+                Err(_) => continue,
+            };
+
+            let url = repo.find_remote("origin").ok().or_else(|| {
+                let remotes = repo.remotes().ok()?;
+                let name = remotes.iter().flatten().next()?;
+                repo.find_remote(name).ok()
+            });
+
+            match url.as_ref().and_then(|r| r.url()) {
+                Some(url) => {
+                    actual.insert(path, canonical_url(url));
+                }
+                None => log::warn!("'{}' has no remote, ignoring it", path.display()),
+            }
+        }
+    }
+
+    let desired = repos
+        .iter()
+        .map(|r| canonical_url(&r.clone_url))
+        .collect::<HashSet<String>>();
+    let present = actual.values().cloned().collect::<HashSet<String>>();
+
+    let missing = repos
+        .into_iter()
+        .filter(|r| !present.contains(&canonical_url(&r.clone_url)))
+        .collect::<Vec<Entry>>();
+    let extra = actual
+        .iter()
+        .filter(|(_, url)| !desired.contains(*url))
+        .collect::<Vec<_>>();
+
+    println!("to clone:");
+    for r in &missing {
+        println!("{}", r.clone_url);
+    }
+
+    println!("to remove:");
+    for (path, url) in &extra {
+        println!("{} ({url})", path.display());
+    }
+
+    if !apply {
+        return Ok(());
+    }
+
+    // a failed clone shouldn't hold back the removals, its error is returned once they're done
+    let cloned = if missing.is_empty() {
+        Ok(())
+    } else {
+        clone_all(&missing, dest, mirror, token, fsck)
+    };
+
+    let mut removable = vec![];
+
+    for (path, _) in extra {
+        let dirty = git2::Repository::open(path).and_then(|repo| {
+            if repo.is_bare() {
+                Ok(false)
+            } else {
+                crate::scan::is_dirty(&repo)
+            }
+        });
+
+        match dirty {
+            Ok(false) => removable.push(path),
+            Ok(true) => log::warn!("'{}' has uncommitted changes, keeping it", path.display()),
+            Err(e) => log::warn!("'{}': {e}, keeping it", path.display()),
+        }
+    }
+
+    if removable.is_empty() {
+        return cloned;
+    }
+
+    eprint!("remove {} repositories? [y/N] ", removable.len());
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+        log::info!("not removing anything");
+        return cloned;
+    }
+
+    for path in removable {
+        log::info!("removing '{}'...", path.display());
+        fs::remove_dir_all(path)
+            .with_context(|| format!("failed to remove '{}'", path.display()))?;
+    }
+
+    cloned
+}
+
+fn write_output(path: &Path, repos: &[&Entry], params: &CloneParams) -> anyhow::Result<()> {
+    let mut output = io::BufWriter::new(
+        fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)?,
+    );
+
+    match params.format {
+        Format::Shell => {
+            let prepand_command = params
+                .prepand_command
+                .as_deref()
+                .unwrap_or(DEFAULT_PREPAND_COMMAND);
+
+            for r in repos {
+                writeln!(output, "{prepand_command} {}", r.clone_url)?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer(&mut output, repos)?;
+            writeln!(output)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Map each name to a filesystem safe file stem, disambiguating names that end up
+/// colliding after sanitization (compared case-insensitively)
+fn file_stems<'a>(names: impl Iterator<Item = &'a str>) -> HashMap<&'a str, String> {
+    let mut used = HashSet::new();
+    let mut stems = HashMap::new();
+
+    for name in names {
+        let sanitized = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+
+        let sanitized = match sanitized.trim_start_matches('.') {
+            "" => "_".to_owned(),
+            s => s.to_owned(),
+        };
+
+        let mut stem = sanitized.clone();
+        let mut n = 1;
+
+        while !used.insert(stem.to_lowercase()) {
+            n += 1;
+            stem = format!("{sanitized}-{n}");
+        }
+
+        if stem != name {
+            log::warn!("owner '{name}' will be written as '{stem}'");
+        }
+
+        stems.insert(name, stem);
+    }
+
+    stems
+}
+
+/// An authenticated client when a token is given, otherwise the default unauthenticated instance
+fn github_client(
+    token: Option<&str>,
+    base_url: Option<&reqwest::Url>,
+) -> anyhow::Result<Arc<octocrab::Octocrab>> {
+    let token = token.filter(|t| !t.trim().is_empty());
+
+    if token.is_none() && base_url.is_none() {
+        return Ok(octocrab::instance());
+    }
+
+    let mut builder = octocrab::Octocrab::builder();
+
+    if let Some(token) = token {
+        log::debug!("using authenticated github client");
+        builder = builder.personal_token(token.to_owned());
+    }
+
+    if let Some(base_url) = base_url {
+        log::debug!("using github api at '{base_url}'");
+        builder = builder
+            .base_uri(base_url.as_str())
+            .with_context(|| format!("invalid github base url '{base_url}'"))?;
+    }
+
+    Ok(Arc::new(builder.build()?))
+}
+
+async fn github(
+    octocrab: &octocrab::Octocrab,
+    group_type: &crate::RepositoryGroupType,
+    name: &str,
+    max: Option<usize>,
+    retries: u32,
+    concurrency: NonZeroUsize,
+) -> anyhow::Result<Vec<Entry>> {
+    log::info!("fetching page 1...");
+    let page = github_page(octocrab, group_type, name, 1, retries)
+        .await
+        .with_context(|| format!("failed to list repositories of '{name}'"))?;
+
+    let pages = page.number_of_pages().unwrap_or(1);
+    log::info!("total pages: {pages}");
+
+    let mut repos = page.items;
+
+    let last_page = match max {
+        Some(max) => pages.min(max.div_ceil(GITHUB_PER_PAGE) as u32),
+        None => pages,
+    };
+
+    let rest = futures::stream::iter(2..=last_page)
+        .map(|page| async move {
+            log::info!("fetching page {page}...");
+            github_page(octocrab, group_type, name, page, retries)
+                .await
+                .map(|p| p.items)
+        })
+        .buffer_unordered(concurrency.get())
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    for mut items in rest {
+        repos.append(&mut items);
+    }
+
+    if let Some(max) = max.filter(|m| *m < repos.len()) {
+        log::info!("stopping at {max} repositories");
+        repos.truncate(max);
+    }
+
+    Ok(repos
+        .into_iter()
+        .filter_map(|r| match (r.clone_url, r.fork) {
+            (Some(url), Some(fork)) => Some(Entry {
+                clone_url: url.as_str().to_owned(),
+                is_fork: fork,
+                owner: r.owner.map(|o| o.login).unwrap_or_else(|| name.to_owned()),
+                name: r.name.clone(),
+                stars: r.stargazers_count.unwrap_or(0),
+                archived: r.archived.unwrap_or(false),
+                language: r
+                    .language
+                    .as_ref()
+                    .and_then(|l| l.as_str())
+                    .map(|l| l.to_owned()),
+                pushed_at: r.pushed_at,
+                mirror_url: r.mirror_url.map(|u| u.as_str().to_owned()),
+            }),
+            (u, f) => {
+                log::error!(
                 "'{}': expected fields to be present, but instead clone_url = {u:?}, fork = {f:?}",
                 r.name
             );
-            None
+                None
+            }
+        })
+        .collect())
+}
+
+async fn github_page(
+    octocrab: &octocrab::Octocrab,
+    group_type: &crate::RepositoryGroupType,
+    name: &str,
+    page: u32,
+    retries: u32,
+) -> anyhow::Result<octocrab::Page<octocrab::models::Repository>> {
+    use octocrab::FromResponse;
+
+    let route = match group_type {
+        crate::RepositoryGroupType::Org => format!("/orgs/{name}/repos"),
+        crate::RepositoryGroupType::User => format!("/users/{name}/repos"),
+    };
+    let route = format!("{route}?per_page={GITHUB_PER_PAGE}&page={page}");
+    let mut attempt = 0;
+
+    loop {
+        let (wait, error) = match octocrab._get(route.as_str()).await {
+            Ok(response) if github_should_retry(response.status(), response.headers()) => {
+                let status = response.status();
+                (
+                    github_retry_after(response.headers()),
+                    anyhow::anyhow!("'{route}' returned {status}"),
+                )
+            }
+            Ok(response) => {
+                let response = octocrab::map_github_error(response).await?;
+                return Ok(octocrab::Page::from_response(response).await?);
+            }
+            Err(e) => (
+                None,
+                anyhow::anyhow!("'{route}': {}", github_error_message(&e)),
+            ),
+        };
+
+        if attempt >= retries {
+            return Err(error);
         }
+
+        attempt += 1;
+        let wait = wait.unwrap_or(Duration::from_secs(1 << attempt.min(6)));
+        log::warn!("{error}, retrying in {wait:?} ({attempt}/{retries})...");
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// octocrab's errors display with a backtrace, so describe them by their source chain instead
+fn github_error_message(error: &octocrab::Error) -> String {
+    let mut messages = vec![];
+    let mut source = std::error::Error::source(error);
+
+    while let Some(s) = source {
+        messages.push(s.to_string());
+        source = s.source();
+    }
+
+    if messages.is_empty() {
+        error
+            .to_string()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned()
+    } else {
+        messages.join(": ")
+    }
+}
+
+/// Server errors, and 403/429 responses caused by the (primary or secondary) rate limit
+fn github_should_retry(status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap) -> bool {
+    status.is_server_error()
+        || (matches!(status.as_u16(), 403 | 429)
+            && (headers.contains_key("retry-after")
+                || headers
+                    .get("x-ratelimit-remaining")
+                    .is_some_and(|r| r == "0")))
+}
+
+/// How long the rate limit headers ask us to wait, if they say so
+fn github_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name)?.to_str().ok()?.parse::<u64>().ok();
+
+    if let Some(seconds) = header("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let reset = header("x-ratelimit-reset")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(reset.saturating_sub(now) + 1))
+}
+
+async fn github_submodules(
+    octocrab: &octocrab::Octocrab,
+    repos: &[Entry],
+) -> anyhow::Result<Vec<Entry>> {
+    let mut submodules = vec![];
+
+    for (i, r) in repos.iter().enumerate() {
+        log::info!(
+            "fetching submodules of '{}/{}' ({}/{})...",
+            r.owner,
+            r.name,
+            i + 1,
+            repos.len()
+        );
+
+        let content = match octocrab
+            .repos(&r.owner, &r.name)
+            .get_content()
+            .path(".gitmodules")
+            .send()
+            .await
+        {
+            Ok(content) => content,
+            Err(octocrab::Error::GitHub { source, .. }) if source.status_code.as_u16() == 404 => {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for gitmodules in content.items.iter().filter_map(|c| c.decoded_content()) {
+            for url in parse_gitmodules(&gitmodules) {
+                let url = resolve_submodule_url(&r.clone_url, url);
+                log::trace!("found submodule of '{}': {url}", r.name);
+
+                submodules.push(Entry {
+                    owner: owner_from_url(&url).unwrap_or(&r.owner).to_owned(),
+                    name: name_from_url(&url).to_owned(),
+                    clone_url: url,
+                    is_fork: false,
+                    stars: 0,
+                    archived: false,
+                    language: None,
+                    pushed_at: None,
+                    mirror_url: None,
+                });
+            }
+        }
+    }
+
+    Ok(submodules)
+}
+
+/// The `url = ...` values of a `.gitmodules` file
+fn parse_gitmodules(content: &str) -> impl Iterator<Item = &str> {
+    content.lines().filter_map(|l| {
+        l.trim()
+            .strip_prefix("url")?
+            .trim_start()
+            .strip_prefix('=')
+            .map(|u| u.trim())
     })
-    .collect())
 }
 
-fn is_in_compare_list(url: &str, compare: &HashSet<String>) -> bool {
-    if compare.contains(url) {
-        return true;
+/// Resolve a submodule url relative to its parent's url, the way git does for `./` and `../` urls
+fn resolve_submodule_url(parent: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_owned();
     }
 
-    if let Some(url) = url.strip_suffix(".git") {
-        if compare.contains(url) {
-            return true;
+    let mut base = parent.trim_end_matches('/').to_owned();
+    let mut rest = url;
+    // going up past the path of a scp-like url (`git@host:repo`) keeps the `:` separator
+    let mut separator = '/';
+
+    loop {
+        if let Some(r) = rest.strip_prefix("./") {
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("../") {
+            rest = r;
+            if let Some(i) = base.rfind(['/', ':']) {
+                separator = if base[i..].starts_with(':') { ':' } else { '/' };
+                base.truncate(i);
+            }
+        } else {
+            break;
+        }
+    }
+
+    format!("{base}{separator}{rest}")
+}
+
+#[derive(Deserialize)]
+struct GitlabProject {
+    path: String,
+    http_url_to_repo: String,
+    forked_from_project: Option<serde_json::Value>,
+    #[serde(default)]
+    star_count: u32,
+    #[serde(default)]
+    archived: bool,
+    last_activity_at: Option<DateTime<Utc>>,
+    namespace: GitlabNamespace,
+}
+
+#[derive(Deserialize)]
+struct GitlabNamespace {
+    full_path: String,
+}
+
+async fn gitlab(
+    host: &str,
+    token: Option<&str>,
+    group_type: &crate::RepositoryGroupType,
+    name: &str,
+    retries: u32,
+) -> anyhow::Result<Vec<Entry>> {
+    let kind = match group_type {
+        crate::RepositoryGroupType::Org => "groups",
+        crate::RepositoryGroupType::User => "users",
+    };
+
+    let client = reqwest::Client::new();
+    let mut next = Some(format!(
+        "{}/api/v4/{kind}/{}/projects?per_page=100",
+        host.trim_end_matches('/'),
+        name.replace('/', "%2F")
+    ));
+    let mut page = 0;
+    let mut projects = vec![];
+
+    while let Some(url) = next {
+        page += 1;
+        log::info!("fetching page {page}...");
+
+        let response = crate::http::fetch(&client, &url, token, retries)
+            .await
+            .with_context(|| format!("failed to list projects of '{name}'"))?;
+        next = crate::http::next_link(response.headers());
+        projects.append(&mut response.json::<Vec<GitlabProject>>().await?);
+    }
+
+    Ok(projects
+        .into_iter()
+        .map(|p| Entry {
+            clone_url: p.http_url_to_repo,
+            is_fork: p.forked_from_project.is_some(),
+            owner: p.namespace.full_path,
+            name: p.path,
+            stars: p.star_count,
+            archived: p.archived,
+            language: None,
+            pushed_at: p.last_activity_at,
+            mirror_url: None,
+        })
+        .collect())
+}
+
+async fn generic(params: &GenericParams, retries: u32) -> anyhow::Result<Vec<Entry>> {
+    // dot separated field path, numeric segments index into arrays
+    fn field<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+        value.pointer(&format!("/{}", path.replace('.', "/")))
+    }
+
+    let client = reqwest::Client::new();
+    let paged = params.list_url_template.contains("{page}");
+    let mut page = 1;
+    let mut next = Some(params.list_url_template.replace("{page}", "1"));
+    let mut entries = vec![];
+
+    while let Some(url) = next {
+        log::info!("fetching page {page}...");
+        let response = crate::http::fetch(&client, &url, params.token.as_deref(), retries).await?;
+        let link = crate::http::next_link(response.headers());
+        let body = response.json::<serde_json::Value>().await?;
+
+        let items = match &params.items_field {
+            Some(f) => field(&body, f),
+            None => Some(&body),
         }
-    } else if compare.contains(&format!("{}.git", url)) {
-        return true;
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| anyhow::anyhow!("'{url}': expected an array of repositories"))?;
+
+        for item in items {
+            let Some(clone_url) = field(item, &params.clone_url_field).and_then(|v| v.as_str())
+            else {
+                log::error!(
+                    "expected field '{}' to be present, but instead got {item}",
+                    params.clone_url_field
+                );
+                continue;
+            };
+
+            let is_fork = match params.fork_field.as_ref().and_then(|f| field(item, f)) {
+                Some(serde_json::Value::Bool(b)) => *b,
+                Some(serde_json::Value::Null) | None => false,
+                Some(_) => true,
+            };
+
+            let owner = params
+                .owner_field
+                .as_ref()
+                .and_then(|f| field(item, f))
+                .and_then(|v| v.as_str())
+                .or_else(|| owner_from_url(clone_url))
+                .unwrap_or_default();
+
+            entries.push(Entry {
+                clone_url: clone_url.to_owned(),
+                is_fork,
+                owner: owner.to_owned(),
+                name: name_from_url(clone_url).to_owned(),
+                stars: 0,
+                archived: false,
+                language: None,
+                pushed_at: None,
+                mirror_url: params
+                    .mirror_url_field
+                    .as_ref()
+                    .and_then(|f| field(item, f))
+                    .and_then(|v| v.as_str())
+                    .filter(|u| !u.is_empty())
+                    .map(|u| u.to_owned()),
+            });
+        }
+
+        next = if let Some(f) = &params.next_field {
+            field(&body, f)
+                .and_then(|v| v.as_str())
+                .map(|u| u.to_owned())
+        } else if paged {
+            (!items.is_empty()).then(|| {
+                params
+                    .list_url_template
+                    .replace("{page}", &(page + 1).to_string())
+            })
+        } else {
+            link
+        };
+
+        page += 1;
     }
 
-    // TODO(tomer) compare across different schemes as well?
+    Ok(entries)
+}
+
+/// The path segment before the repository name, e.g. `owner` in `https://host/owner/repo.git`
+fn owner_from_url(url: &str) -> Option<&str> {
+    let mut segments = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .filter(|s| !s.is_empty());
+
+    segments.next()?;
+    segments.next()
+}
+
+/// The last path segment without a `.git` suffix, e.g. `repo` in `https://host/owner/repo.git`
+fn name_from_url(url: &str) -> &str {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or(url);
+
+    name.strip_suffix(".git").unwrap_or(name)
+}
+
+/// `https://host/owner/repo[.git]` to `git@host:owner/repo.git`
+fn ssh_url(url: &str) -> Option<String> {
+    let (host, path) = url.strip_prefix("https://")?.split_once('/')?;
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    if host.is_empty() || host.contains(['@', ':']) || !path.contains('/') {
+        return None;
+    }
+
+    Some(format!("git@{host}:{path}.git"))
+}
 
-    false
+/// `compare` holds urls in their canonical form, see [`canonical_url`]
+fn is_in_compare_list(url: &str, compare: &HashSet<String>) -> bool {
+    compare.contains(&canonical_url(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gitmodules_urls() {
+        let content = "[submodule \"x\"]\n\tpath = x\n\turl = ../x.git\n\
+                       [submodule \"y\"]\n\turl=https://host/o/y\n";
+
+        assert_eq!(
+            parse_gitmodules(content).collect::<Vec<_>>(),
+            ["../x.git", "https://host/o/y"]
+        );
+    }
+
+    #[test]
+    fn relative_submodule_urls() {
+        assert_eq!(
+            resolve_submodule_url("https://host/o/r", "../sibling.git"),
+            "https://host/o/sibling.git"
+        );
+        assert_eq!(
+            resolve_submodule_url("git@host:o/r", "../sibling.git"),
+            "git@host:o/sibling.git"
+        );
+        assert_eq!(
+            resolve_submodule_url("git@host:r", "../sibling.git"),
+            "git@host:sibling.git"
+        );
+        assert_eq!(
+            resolve_submodule_url("https://host/o/r", "./x"),
+            "https://host/o/r/x"
+        );
+        assert_eq!(
+            resolve_submodule_url("https://host/o/r", "https://other/o/x"),
+            "https://other/o/x"
+        );
+    }
+
+    #[test]
+    fn file_stems_are_filesystem_safe() {
+        let stems = file_stems(["a/b", ".hidden", ""].into_iter());
+
+        assert_eq!(stems["a/b"], "a_b");
+        assert_eq!(stems[".hidden"], "hidden");
+        assert_eq!(stems[""], "_");
+    }
+
+    #[test]
+    fn file_stems_collide_case_insensitively() {
+        let stems = file_stems(["Foo", "foo"].into_iter());
+
+        assert_eq!(stems["Foo"], "Foo");
+        assert_eq!(stems["foo"], "foo-2");
+    }
 }
@@ -0,0 +1,25 @@
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Open a file for reading, transparently decompressing it when it is gzip or zstd compressed
+/// (detected by magic bytes, so the extension doesn't matter)
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let header = reader.fill_buf()?;
+
+    Ok(if header.starts_with(GZIP_MAGIC) {
+        log::debug!("reading '{}' as gzip", path.display());
+        Box::new(io::BufReader::new(flate2::bufread::MultiGzDecoder::new(
+            reader,
+        )))
+    } else if header.starts_with(ZSTD_MAGIC) {
+        log::debug!("reading '{}' as zstd", path.display());
+        Box::new(io::BufReader::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Box::new(reader)
+    })
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+/// GET a url, retrying with exponential backoff (or the server's `Retry-After`) on
+/// connection errors, 429 and 5xx responses
+pub async fn fetch(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    retries: u32,
+) -> anyhow::Result<reqwest::Response> {
+    let mut attempt = 0;
+
+    loop {
+        let mut request = client.get(url);
+
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        let (wait, error) = match request.send().await {
+            Ok(response)
+                if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || response.status().is_server_error() =>
+            {
+                let wait = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .map(Duration::from_secs);
+
+                (
+                    wait,
+                    anyhow::anyhow!("'{url}' returned {}", response.status()),
+                )
+            }
+            Ok(response) => return Ok(response.error_for_status()?),
+            Err(e) => (None, e.into()),
+        };
+
+        if attempt >= retries {
+            return Err(error);
+        }
+
+        attempt += 1;
+        let wait = wait.unwrap_or(Duration::from_secs(1 << attempt.min(6)));
+        log::warn!("{error}, retrying in {wait:?} ({attempt}/{retries})...");
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Extract the `rel="next"` url from a `Link` header
+pub fn next_link(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get(reqwest::header::LINK)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find(|l| l.contains("rel=\"next\""))?
+        .split(';')
+        .next()?
+        .trim()
+        .strip_prefix('<')?
+        .strip_suffix('>')
+        .map(|u| u.to_owned())
+}
@@ -0,0 +1,128 @@
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::{fs, path::PathBuf};
+
+const API_URL: &str = "https://huggingface.co/api";
+
+#[derive(Args, Debug)]
+pub struct HuggingfaceParams {
+    /// Organization or user to enumerate
+    author: String,
+
+    /// Kind of repositories to enumerate
+    #[arg(short, long, value_enum, default_value = "model")]
+    kind: Kind,
+
+    /// Access token, needed for private or gated repositories
+    #[arg(long, env = "HF_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Output repository list to a file instead of stdout
+    #[arg(short, long)]
+    output_file: Option<PathBuf>,
+
+    /// Continue an interrupted run from its checkpoint file
+    #[arg(long, requires = "output_file")]
+    resume: bool,
+
+    /// How many times to retry a failed page request
+    #[arg(long, default_value = "3")]
+    retries: u32,
+}
+
+#[derive(ValueEnum, Debug, Clone)]
+enum Kind {
+    Model,
+    Dataset,
+    Space,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    id: String,
+}
+
+pub fn huggingface(params: HuggingfaceParams) -> anyhow::Result<()> {
+    let endpoint = match params.kind {
+        Kind::Model => "models",
+        Kind::Dataset => "datasets",
+        Kind::Space => "spaces",
+    };
+
+    let mut next = Some(format!(
+        "{API_URL}/{endpoint}?author={}&limit=1000",
+        params.author
+    ));
+
+    let checkpoint = params.output_file.as_ref().map(|o| {
+        let mut c = o.clone().into_os_string();
+        c.push(".checkpoint");
+        PathBuf::from(c)
+    });
+
+    let mut output: Box<dyn Write> = match (&params.output_file, &checkpoint) {
+        (Some(output), Some(checkpoint)) if params.resume => {
+            if !checkpoint.exists() {
+                anyhow::bail!(
+                    "no checkpoint found at '{}', nothing to resume",
+                    checkpoint.display()
+                );
+            }
+
+            next = Some(fs::read_to_string(checkpoint)?.trim().to_owned());
+            log::info!("resuming from checkpoint '{}'", checkpoint.display());
+
+            Box::new(io::BufWriter::new(
+                fs::OpenOptions::new().append(true).open(output)?,
+            ))
+        }
+        (Some(output), _) => Box::new(io::BufWriter::new(
+            fs::OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(output)?,
+        )),
+        (None, _) => Box::new(io::stdout().lock()),
+    };
+
+    let client = reqwest::Client::new();
+    let runtime = tokio::runtime::Runtime::new()?;
+    let mut total = 0;
+    let mut page = 0;
+
+    while let Some(url) = next {
+        page += 1;
+        log::info!("fetching page {page}...");
+
+        let response = runtime.block_on(crate::http::fetch(
+            &client,
+            &url,
+            params.token.as_deref(),
+            params.retries,
+        ))?;
+
+        next = crate::http::next_link(response.headers());
+
+        let repos = runtime.block_on(response.json::<Vec<Repository>>())?;
+        total += repos.len();
+
+        for r in repos {
+            writeln!(output, "{}", r.id)?;
+        }
+
+        output.flush()?;
+
+        if let Some(checkpoint) = &checkpoint {
+            match &next {
+                Some(next) => fs::write(checkpoint, next)?,
+                None if checkpoint.exists() => fs::remove_file(checkpoint)?,
+                None => {}
+            }
+        }
+    }
+
+    log::info!("got {total} repositories");
+
+    Ok(())
+}
@@ -1,7 +1,12 @@
 use clap::{Parser, Subcommand, ValueEnum};
 
 mod clone;
+mod compression;
+mod http;
+mod huggingface;
+mod merge;
 mod scan;
+mod url;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -15,12 +20,20 @@ struct Cli {
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     /// Scan local filesystem for repositories
     Scan(scan::ScanParams),
 
     /// Clone repositories
     Clone(clone::CloneParams),
+
+    /// List huggingface repositories of an organization or user
+    Huggingface(huggingface::HuggingfaceParams),
+
+    /// Merge scan output files from several machines, keeping the most recent entry of each
+    /// repository
+    Merge(merge::MergeParams),
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -31,10 +44,37 @@ enum Platform {
 
         input: String,
     },
+
+    Gitlab {
+        #[arg(value_enum)]
+        group_type: RepositoryGroupType,
+
+        input: String,
+
+        /// Url of the GitLab instance
+        #[arg(long, default_value = "https://gitlab.com")]
+        host: String,
+
+        /// Access token, needed for private groups and projects
+        #[arg(long, env = "GITLAB_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+
+    /// Any forge with a paginated JSON REST API listing repositories
+    ///
+    /// Examples:
+    ///   gitea/forgejo: 'https://HOST/api/v1/orgs/ORG/repos?limit=50&page={page}'
+    ///   gitlab:        'https://HOST/api/v4/groups/GROUP/projects?per_page=100&page={page}'
+    ///                  --clone-url-field http_url_to_repo --fork-field forked_from_project
+    ///   bitbucket:     'https://api.bitbucket.org/2.0/repositories/WORKSPACE' --items-field values
+    ///                  --next-field next --clone-url-field links.clone.0.href --fork-field parent
+    #[command(verbatim_doc_comment)]
+    Generic(clone::GenericParams),
 }
 
 #[derive(ValueEnum, Debug, Clone)]
 enum RepositoryGroupType {
+    #[value(alias = "group")]
     Org,
     User,
 }
@@ -50,5 +90,7 @@ fn main() -> anyhow::Result<()> {
     match cli.command {
         Commands::Scan(params) => scan::scan(params),
         Commands::Clone(params) => clone::clone(params),
+        Commands::Huggingface(params) => huggingface::huggingface(params),
+        Commands::Merge(params) => merge::merge(params),
     }
 }
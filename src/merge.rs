@@ -0,0 +1,194 @@
+use crate::scan::{read_rows, write_row, Row};
+use crate::url::canonical_url;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::Args;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub struct MergeParams {
+    /// Scan output files to merge, csv or json by extension
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Merged output file, csv or json by extension
+    #[arg(short, long)]
+    output_file: PathBuf,
+
+    /// Overwrite an existing output file
+    #[arg(long)]
+    force: bool,
+}
+
+/// Why an entry replaced another one with the same canonical url
+#[derive(Default)]
+struct Conflicts {
+    by_commit_date: usize,
+    by_repo_fetch: usize,
+    kept_first: usize,
+}
+
+pub fn merge(params: MergeParams) -> anyhow::Result<()> {
+    if params.output_file.exists() && !params.force {
+        anyhow::bail!(
+            "'{}' already exists, pass --force to overwrite it",
+            params.output_file.display()
+        );
+    }
+
+    let mut merged: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+    let mut conflicts = Conflicts::default();
+    let mut total = 0;
+
+    for file in &params.files {
+        let entries = read_entries(file)?;
+        log::info!("read {} entries from '{}'", entries.len(), file.display());
+        total += entries.len();
+
+        for entry in entries {
+            let Some(url) = entry["remote_url"].as_str() else {
+                log::warn!("'{}': skipping an entry without remote_url", file.display());
+                continue;
+            };
+
+            let key = canonical_url(url);
+
+            match merged.get(&key) {
+                None => {
+                    merged.insert(key, entry);
+                }
+                Some(existing) => {
+                    if newer(&entry, existing, &mut conflicts) {
+                        merged.insert(key, entry);
+                    }
+                }
+            }
+        }
+    }
+
+    write_entries(&params.output_file, merged.values())?;
+
+    log::info!(
+        "merged {total} entries from {} files into {} repositories",
+        params.files.len(),
+        merged.len()
+    );
+    log::info!(
+        "resolved {} conflicts: {} by last_commit_date, {} by last_repo_fetch, {} kept the first entry",
+        conflicts.by_commit_date + conflicts.by_repo_fetch + conflicts.kept_first,
+        conflicts.by_commit_date,
+        conflicts.by_repo_fetch,
+        conflicts.kept_first
+    );
+
+    Ok(())
+}
+
+/// Whether `entry` should replace `existing`: the most recent `last_commit_date` wins, then the
+/// most recent `last_repo_fetch`, and the entry seen first when neither tells them apart
+fn newer(
+    entry: &serde_json::Value,
+    existing: &serde_json::Value,
+    conflicts: &mut Conflicts,
+) -> bool {
+    let commit = (
+        date(entry, "last_commit_date"),
+        date(existing, "last_commit_date"),
+    );
+    let fetch = (
+        date(entry, "last_repo_fetch"),
+        date(existing, "last_repo_fetch"),
+    );
+
+    if commit.0 != commit.1 {
+        conflicts.by_commit_date += 1;
+        commit.0 > commit.1
+    } else if fetch.0 != fetch.1 {
+        conflicts.by_repo_fetch += 1;
+        fetch.0 > fetch.1
+    } else {
+        conflicts.kept_first += 1;
+        false
+    }
+}
+
+fn date(entry: &serde_json::Value, field: &str) -> Option<DateTime<Utc>> {
+    let date = DateTime::parse_from_rfc3339(entry[field].as_str()?).ok()?;
+    Some(date.with_timezone(&Utc))
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "json")
+}
+
+/// Entries of a scan output file, csv rows are read into the same shape as the json entries
+fn read_entries(path: &Path) -> anyhow::Result<Vec<serde_json::Value>> {
+    if is_json(path) {
+        return serde_json::from_reader(fs::File::open(path)?)
+            .with_context(|| format!("failed to parse '{}'", path.display()));
+    }
+
+    read_rows(path)?
+        .into_iter()
+        .map(|r| {
+            let mut entry = serde_json::to_value(r)?;
+            if entry["last_repo_fetch"] == "never" {
+                entry["last_repo_fetch"] = serde_json::Value::Null;
+            }
+            Ok(entry)
+        })
+        .collect()
+}
+
+fn write_entries<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = &'a serde_json::Value>,
+) -> anyhow::Result<()> {
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+
+    if is_json(path) {
+        serde_json::to_writer(&mut file, &entries.collect::<Vec<_>>())?;
+        writeln!(file)?;
+    } else {
+        let mut header_pending = true;
+
+        for entry in entries {
+            let mut entry = entry.clone();
+            if entry["last_repo_fetch"].is_null() {
+                entry["last_repo_fetch"] = "never".into();
+            }
+
+            let row: Row = serde_json::from_value(entry)?;
+            write_row(&mut file, &mut header_pending, &row)?;
+        }
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn most_recent_entry_wins() {
+        let mut conflicts = Conflicts::default();
+        let old = json!({"last_commit_date": "2024-01-01T00:00:00Z", "last_repo_fetch": null});
+        let new = json!({"last_commit_date": "2025-01-01T00:00:00Z", "last_repo_fetch": null});
+        let fetched = json!({"last_commit_date": "2025-01-01T00:00:00Z", "last_repo_fetch": "2025-02-01T00:00:00Z"});
+
+        assert!(newer(&new, &old, &mut conflicts));
+        assert!(!newer(&old, &new, &mut conflicts));
+        assert!(newer(&fetched, &new, &mut conflicts));
+        assert!(!newer(&new, &new, &mut conflicts));
+
+        assert_eq!(conflicts.by_commit_date, 2);
+        assert_eq!(conflicts.by_repo_fetch, 1);
+        assert_eq!(conflicts.kept_first, 1);
+    }
+}
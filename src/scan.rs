@@ -1,8 +1,15 @@
-use clap::Args;
+use anyhow::Context;
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use clap::{Args, ValueEnum};
 use git2::Repository;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::{collections::HashMap, fs};
+use std::sync::Mutex;
 
 #[derive(Args, Debug)]
 pub struct ScanParams {
@@ -14,6 +21,14 @@ pub struct ScanParams {
     #[arg(short, long)]
     output_file: Option<PathBuf>,
 
+    /// Add to an existing output file, skipping repositories it already has
+    #[arg(long, requires = "output_file", conflicts_with = "force")]
+    append: bool,
+
+    /// Overwrite an existing output file
+    #[arg(long, requires = "output_file")]
+    force: bool,
+
     /// Print output
     #[arg(long)]
     print_output: bool,
@@ -22,29 +37,612 @@ pub struct ScanParams {
     #[arg(long)]
     print_duplicates: bool,
 
+    /// Exit with an error when duplicate repositories are found
+    #[arg(long)]
+    fail_on_duplicates: bool,
+
     /// Print irrelevant
     #[arg(long)]
     print_irrelevant: bool,
 
-    /// How deep subdirectories to scan
+    /// Print whether HEAD is signed along with its author and committer (included in --print-json)
+    #[arg(long)]
+    print_signing: bool,
+
+    /// Print the subject line of HEAD (included in --print-json)
+    #[arg(long)]
+    print_subject: bool,
+
+    /// Maximum length of the printed subject line
+    #[arg(long, default_value = "72", requires = "print_subject")]
+    subject_length: usize,
+
+    /// Check whether each repository has uncommitted changes or untracked files (included in
+    /// --print-json and the csv and json output files, bare repositories are skipped)
+    #[arg(long)]
+    check_dirty: bool,
+
+    /// Print repositories with uncommitted changes or untracked files
+    #[arg(long, requires = "check_dirty")]
+    print_dirty: bool,
+
+    /// Print repositories whose remote url is empty or whitespace
+    #[arg(long)]
+    print_invalid: bool,
+
+    /// Print repositories, duplicates and irrelevant paths as a single JSON object
+    #[arg(long, conflicts_with_all = ["print_output", "print_duplicates", "print_irrelevant", "print_invalid"])]
+    print_json: bool,
+
+    /// How deep subdirectories to scan, 0 and 1 both only look at the directory's own entries
     #[arg(long, default_value = "3")]
     depth: usize,
+
+    /// Remote to record, can be repeated to define a priority order (falls back to the first remote)
+    #[arg(long, default_value = "origin")]
+    prefer_remote: Vec<String>,
+
+    /// Include the local remote, branch upstream and core config of each repository (only in
+    /// --print-json and the json output file)
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Stop after finding this many repositories, meant for quick testing since which ones are
+    /// found first is arbitrary
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Format of the output file: urls is a remote url per line (what clone --compare-file reads),
+    /// csv and json also include the path (relative to the scanned directory), branch, last commit
+    /// and fetch, and whatever else was collected
+    #[arg(long, value_enum, default_value = "urls", requires = "output_file")]
+    format: Format,
+
+    /// Previous csv or json archive of the same directory, its last fetch times are kept for
+    /// repositories where FETCH_HEAD no longer tells
+    #[arg(long)]
+    merge: Option<PathBuf>,
+
+    /// Number of threads to scan with, defaults to the number of cores
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Sum the size of the files of each repository, printed repositories are then sorted by size
+    #[arg(long)]
+    with_size: bool,
+
+    /// Keep only one repository (the one with the smallest path) for each remote, comparing urls
+    /// regardless of scheme, credentials or a `.git` suffix
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Skip files and directories whose name or path matches this glob, can be repeated
+    /// (e.g. --exclude node_modules --exclude '**/.cargo')
+    #[arg(long)]
+    exclude: Vec<Glob>,
+
+    /// Look inside tar and zip archives for a git repository at their root (slow)
+    #[arg(long)]
+    peek_archives: bool,
 }
 
-#[derive(Clone)]
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+enum Format {
+    #[default]
+    Urls,
+    Csv,
+    Json,
+}
+
+#[derive(Clone, Serialize)]
 struct Entry {
     path: PathBuf,
     remote_url: String,
+    remote_name: String,
+    /// Push url of the remote, only set when it differs from `remote_url`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    push_url: Option<String>,
+    /// Every remote of the repository as `(name, url)`, including the recorded one
+    remotes: Vec<(String, String)>,
+    last_commit_hash: Option<String>,
+    last_commit_date: Option<DateTime<Utc>>,
+    /// Modification time of `FETCH_HEAD`, `None` when the repository was never fetched
+    last_repo_fetch: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    signing: Option<Signing>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    head_subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dirty: Option<bool>,
+    /// Branch HEAD points to, `(detached)` when it points to a commit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    branch: Option<String>,
+    /// Total size of the files in the repository directory (including `.git`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_bytes: Option<u64>,
+}
+
+/// A line of the csv output, the nested and per-remote details only fit in json
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Row {
+    remote_url: String,
+    path: PathBuf,
+    remote_name: String,
+    branch: Option<String>,
+    last_commit_hash: Option<String>,
+    last_commit_date: Option<DateTime<Utc>>,
+    /// `never` when the repository was never fetched
+    last_repo_fetch: String,
+    dirty: Option<bool>,
+    size_bytes: Option<u64>,
+}
+
+impl Row {
+    fn new(entry: &Entry, directory: &Path) -> Self {
+        Row {
+            remote_url: entry.remote_url.clone(),
+            path: entry
+                .path
+                .strip_prefix(directory)
+                .unwrap_or(&entry.path)
+                .to_owned(),
+            remote_name: entry.remote_name.clone(),
+            branch: entry.branch.clone(),
+            last_commit_hash: entry.last_commit_hash.clone(),
+            last_commit_date: entry.last_commit_date,
+            last_repo_fetch: entry
+                .last_repo_fetch
+                .map(|f| f.to_rfc3339_opts(SecondsFormat::Secs, true))
+                .unwrap_or_else(|| "never".to_owned()),
+            dirty: entry.dirty,
+            size_bytes: entry.size_bytes,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct Signing {
+    head_signed: bool,
+    head_author_email: Option<String>,
+    head_committer_email: Option<String>,
+}
+
+#[derive(Default)]
+struct Found {
+    repositories: Vec<Entry>,
+    irrelevant: Vec<PathBuf>,
+    /// Repositories with an empty or whitespace remote url
+    invalid: Vec<PathBuf>,
+    /// Archive files containing a git repository
+    archived: Vec<PathBuf>,
+    output: Option<Output>,
+    /// Last fetch times from the --merge archive, by absolute path
+    previous_fetch: HashMap<PathBuf, DateTime<Utc>>,
+}
+
+/// The output file is written to a `.partial` sibling and only renamed over `path` once the scan
+/// completes, so an existing file is never lost to a failing or interrupted scan
+struct Output {
+    path: PathBuf,
+    partial: PathBuf,
+    /// Repositories are written and flushed here as soon as they are found (urls and csv), so an
+    /// interrupted scan keeps its progress. A complete scan rewrites it sorted, since entries are
+    /// found in parallel. Json is a single array and is only written by `finish`, also when the
+    /// scan fails
+    file: io::BufWriter<fs::File>,
+    format: Format,
+    directory: PathBuf,
+    /// Whether the csv header still has to be written
+    header_pending: bool,
+    /// Remote urls already in the output file when appending (urls)
+    existing_urls: Vec<String>,
+    /// Rows already in the output file when appending (csv)
+    existing_rows: Vec<Row>,
+    /// Entries already in the output file when appending (json)
+    existing_entries: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    repositories: &'a [Entry],
+    duplicates: &'a [Entry],
+    irrelevant: &'a [PathBuf],
+    invalid: &'a [PathBuf],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archived: Option<&'a [PathBuf]>,
+}
+
+impl Found {
+    fn push(&mut self, mut entry: Entry) -> anyhow::Result<()> {
+        if entry.last_repo_fetch.is_none() {
+            entry.last_repo_fetch = self.previous_fetch.get(&entry.path).copied();
+        }
+
+        if let Some(output) = &mut self.output {
+            output.stream(&entry)?;
+        }
+
+        self.repositories.push(entry);
+        Ok(())
+    }
+
+    fn is_full(&self, limit: Option<usize>) -> bool {
+        limit.is_some_and(|l| self.repositories.len() >= l)
+    }
+
+    /// Write out whatever wasn't written as it was found and, when the scan is `complete`, move the
+    /// output into place
+    fn finish(&mut self, complete: bool) -> anyhow::Result<()> {
+        match &mut self.output {
+            Some(output) => output.finish(&self.repositories, complete),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Output {
+    fn open(params: &ScanParams, path: &Path) -> anyhow::Result<Self> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        if path.exists() && !params.append && !params.force {
+            anyhow::bail!(
+                "'{}' already exists, pass --append or --force to reuse it",
+                path.display()
+            );
+        }
+
+        let mut output = Output {
+            path: path.to_owned(),
+            file: io::BufWriter::new(fs::File::create(&partial)?),
+            partial,
+            format: params.format,
+            directory: params.directory.clone(),
+            header_pending: true,
+            existing_urls: vec![],
+            existing_rows: vec![],
+            existing_entries: vec![],
+        };
+
+        if params.append && path.exists() {
+            match params.format {
+                Format::Urls => {
+                    output.existing_urls = fs::read_to_string(path)?
+                        .lines()
+                        .map(|l| l.trim().to_owned())
+                        .filter(|l| !l.is_empty())
+                        .collect();
+                }
+                Format::Csv => {
+                    output.existing_rows = read_rows(path)?;
+                }
+                Format::Json => {
+                    // the array is rewritten as a whole, so load what's there first
+                    output.existing_entries = serde_json::from_reader(fs::File::open(path)?)
+                        .with_context(|| format!("failed to parse '{}'", path.display()))?;
+                }
+            }
+
+            output.write_existing()?;
+            log::info!("appending to '{}'", path.display());
+        }
+
+        Ok(output)
+    }
+
+    fn write_existing(&mut self) -> anyhow::Result<()> {
+        for url in &self.existing_urls {
+            writeln!(self.file, "{url}")?;
+        }
+
+        for row in &self.existing_rows {
+            write_row(&mut self.file, &mut self.header_pending, row)?;
+        }
+
+        Ok(())
+    }
+
+    fn stream(&mut self, entry: &Entry) -> anyhow::Result<()> {
+        match self.format {
+            Format::Urls => {
+                writeln!(self.file, "{}", entry.remote_url)?;
+                self.file.flush()?;
+            }
+            Format::Csv => write_row(
+                &mut self.file,
+                &mut self.header_pending,
+                &Row::new(entry, &self.directory),
+            )?,
+            Format::Json => {}
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self, repositories: &[Entry], complete: bool) -> anyhow::Result<()> {
+        if complete && self.format != Format::Json {
+            // anything still buffered would otherwise land in the recreated file when it's dropped
+            self.file.flush()?;
+            self.file = io::BufWriter::new(fs::File::create(&self.partial)?);
+            self.header_pending = true;
+            self.write_existing()?;
+        }
+
+        match self.format {
+            Format::Urls if complete => {
+                let existing = self
+                    .existing_urls
+                    .iter()
+                    .map(|u| u.as_str())
+                    .collect::<HashSet<&str>>();
+
+                for e in repositories
+                    .iter()
+                    .filter(|e| !existing.contains(e.remote_url.as_str()))
+                {
+                    writeln!(self.file, "{}", e.remote_url)?;
+                }
+            }
+            Format::Csv if complete => {
+                let existing = self
+                    .existing_rows
+                    .iter()
+                    .map(|r| (r.remote_url.clone(), r.path.clone()))
+                    .collect::<HashSet<_>>();
+
+                for e in repositories {
+                    let row = Row::new(e, &self.directory);
+
+                    if !existing.contains(&(row.remote_url.clone(), row.path.clone())) {
+                        write_row(&mut self.file, &mut self.header_pending, &row)?;
+                    }
+                }
+            }
+            Format::Json => {
+                let key = |v: &serde_json::Value| (v["path"].clone(), v["remote_url"].clone());
+                let existing = self
+                    .existing_entries
+                    .iter()
+                    .map(key)
+                    .collect::<HashSet<_>>();
+
+                let mut entries = self.existing_entries.clone();
+
+                for e in repositories {
+                    let e = serde_json::to_value(Entry {
+                        path: e
+                            .path
+                            .strip_prefix(&self.directory)
+                            .unwrap_or(&e.path)
+                            .to_owned(),
+                        ..e.clone()
+                    })?;
+
+                    if !existing.contains(&key(&e)) {
+                        entries.push(e);
+                    }
+                }
+
+                serde_json::to_writer(&mut self.file, &entries)?;
+                writeln!(self.file)?;
+            }
+            _ => {}
+        }
+
+        self.file.flush()?;
+
+        if complete {
+            fs::rename(&self.partial, &self.path)?;
+        } else {
+            log::info!("partial output kept in '{}'", self.partial.display());
+        }
+
+        Ok(())
+    }
+}
+
+/// Last fetch times recorded in a csv or json (by extension) scan output
+fn previous_fetch(
+    archive: &Path,
+    directory: &Path,
+) -> anyhow::Result<HashMap<PathBuf, DateTime<Utc>>> {
+    let is_json = archive.extension().is_some_and(|e| e == "json");
+
+    let fetches: Vec<(PathBuf, String)> = if is_json {
+        let entries: Vec<serde_json::Value> = serde_json::from_reader(fs::File::open(archive)?)
+            .with_context(|| format!("failed to parse '{}'", archive.display()))?;
+
+        entries
+            .iter()
+            .filter_map(|e| {
+                Some((
+                    PathBuf::from(e["path"].as_str()?),
+                    e["last_repo_fetch"].as_str()?.to_owned(),
+                ))
+            })
+            .collect()
+    } else {
+        read_rows(archive)?
+            .into_iter()
+            .map(|r| (r.path, r.last_repo_fetch))
+            .collect()
+    };
+
+    Ok(fetches
+        .into_iter()
+        .filter_map(|(path, fetch)| {
+            let fetch = DateTime::parse_from_rfc3339(&fetch).ok()?;
+            Some((directory.join(path), fetch.with_timezone(&Utc)))
+        })
+        .collect())
+}
+
+pub(crate) fn read_rows(path: &Path) -> anyhow::Result<Vec<Row>> {
+    csv::Reader::from_path(path)?
+        .into_deserialize()
+        .collect::<Result<Vec<Row>, _>>()
+        .with_context(|| format!("failed to parse '{}'", path.display()))
+}
+
+/// Write a csv row, preceded by the header when it's the first one
+pub(crate) fn write_row(
+    file: &mut io::BufWriter<fs::File>,
+    header_pending: &mut bool,
+    row: &Row,
+) -> anyhow::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(*header_pending)
+        .from_writer(file);
+
+    writer.serialize(row)?;
+    writer.flush()?;
+    *header_pending = false;
+
+    Ok(())
 }
 
 pub fn scan(params: ScanParams) -> anyhow::Result<()> {
-    let (repositories, irrelevant) = local(&params.directory, 0, params.depth - 1)?;
+    if params.dump_config && !params.print_json && params.format != Format::Json {
+        anyhow::bail!("--dump-config requires --print-json or --format json");
+    }
+
+    let mut found = Found::default();
+
+    if let Some(archive) = &params.merge {
+        found.previous_fetch = previous_fetch(archive, &params.directory)?;
+        log::info!(
+            "loaded {} last fetch times from '{}'",
+            found.previous_fetch.len(),
+            archive.display()
+        );
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(params.jobs.unwrap_or(0))
+        .build()?;
+
+    if let Some(output) = &params.output_file {
+        found.output = Some(Output::open(&params, output)?);
+    }
+
+    let mut exclude = GlobSetBuilder::new();
+
+    for pattern in &params.exclude {
+        exclude.add(pattern.clone());
+    }
+
+    let exclude = exclude.build()?;
+
+    let found = Mutex::new(found);
+    let result = pool.install(|| local_root(&params, &exclude, &found));
+    let mut found = found.into_inner().unwrap();
+
+    if let Err(e) = result {
+        log::error!(
+            "scan failed after finding {} repositories",
+            found.repositories.len()
+        );
+
+        found.finish(false)?;
+        return Err(e);
+    }
+
+    if found.is_full(params.limit) {
+        log::info!(
+            "stopped after reaching the limit of {} repositories",
+            found.repositories.len()
+        );
+    }
+
+    // entries are found in parallel, sort them so the printed sections are stable
+    found.repositories.sort_by(|a, b| a.path.cmp(&b.path));
+    found.irrelevant.sort();
+    found.invalid.sort();
+    found.archived.sort();
+
+    if params.dedupe {
+        dedupe(&mut found.repositories);
+    }
+
+    found.finish(true)?;
+
+    let Found {
+        repositories,
+        irrelevant,
+        invalid,
+        archived,
+        ..
+    } = found;
+
     let duplicates = find_duplicates(&repositories);
 
     if params.print_output {
+        let mut printed = repositories.iter().collect::<Vec<&Entry>>();
+
+        if params.with_size {
+            printed.sort_by_key(|e| std::cmp::Reverse(e.size_bytes));
+        }
+
         println!("repositories:");
+        for e in printed {
+            let size = e
+                .size_bytes
+                .map(|s| format!(" {s} bytes"))
+                .unwrap_or_default();
+
+            if let Some(push_url) = &e.push_url {
+                println!(
+                    "{} ({}, push: {push_url}){size}",
+                    e.remote_url, e.remote_name
+                );
+            } else {
+                println!("{} ({}){size}", e.remote_url, e.remote_name);
+            }
+
+            for (name, url) in e.remotes.iter().filter(|(n, _)| *n != e.remote_name) {
+                println!("    {url} ({name})");
+            }
+        }
+
+        if params.peek_archives {
+            println!("archived repositories:");
+            for a in &archived {
+                println!("{}", a.display());
+            }
+        }
+    }
+
+    if params.print_signing && !params.print_json {
+        println!("signing:");
         for e in &repositories {
-            println!("{}", e.remote_url);
+            if let Some(s) = &e.signing {
+                println!(
+                    "{} {} {} ({})",
+                    if s.head_signed { "signed" } else { "unsigned" },
+                    s.head_author_email.as_deref().unwrap_or("-"),
+                    s.head_committer_email.as_deref().unwrap_or("-"),
+                    e.path.display()
+                );
+            }
+        }
+    }
+
+    if params.print_subject && !params.print_json {
+        println!("subjects:");
+        for e in &repositories {
+            if let Some(subject) = &e.head_subject {
+                println!("{subject} ({})", e.path.display());
+            }
+        }
+    }
+
+    if params.print_dirty && !params.print_json {
+        println!("dirty:");
+        for e in repositories.iter().filter(|e| e.dirty == Some(true)) {
+            println!("{} ({})", e.remote_url, e.path.display());
         }
     }
 
@@ -62,117 +660,109 @@ pub fn scan(params: ScanParams) -> anyhow::Result<()> {
         }
     }
 
-    if let Some(output) = &params.output_file {
-        let mut output = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .create_new(true)
-                .write(true)
-                .open(output)?,
-        );
-
-        for e in &repositories {
-            writeln!(output, "{}", e.remote_url)?;
+    if params.print_invalid {
+        println!("invalid:");
+        for i in &invalid {
+            println!("{}", i.display());
         }
     }
 
+    if params.print_json {
+        let report = Report {
+            repositories: &repositories,
+            duplicates: &duplicates,
+            irrelevant: &irrelevant,
+            invalid: &invalid,
+            archived: params.peek_archives.then_some(&archived),
+        };
+
+        println!("{}", serde_json::to_string(&report)?);
+    }
+
     log::info!(
         "found {} repositories with {} duplicates",
         repositories.len(),
         duplicates.len()
     );
 
+    if params.peek_archives {
+        log::info!("found {} archived repositories", archived.len());
+    }
+
+    if !invalid.is_empty() {
+        log::warn!(
+            "skipped {} repositories with an empty remote url",
+            invalid.len()
+        );
+    }
+
+    if params.fail_on_duplicates && !duplicates.is_empty() {
+        let mut groups: HashMap<&str, Vec<&Path>> = HashMap::new();
+
+        for e in &duplicates {
+            groups.entry(&e.remote_url).or_default().push(&e.path);
+        }
+
+        for (url, paths) in &groups {
+            log::error!("duplicate repository: {url}");
+            for p in paths {
+                log::error!("    {}", p.display());
+            }
+        }
+
+        anyhow::bail!(
+            "found {} duplicate repositories of {} remotes",
+            duplicates.len(),
+            groups.len()
+        );
+    }
+
     Ok(())
 }
 
+/// Scan `params.directory`, a depth of 0 is treated like 1 and only looks at its own entries
+fn local_root(params: &ScanParams, exclude: &GlobSet, found: &Mutex<Found>) -> anyhow::Result<()> {
+    local(
+        params,
+        exclude,
+        &params.directory,
+        0,
+        params.depth.saturating_sub(1),
+        found,
+    )
+}
+
 fn local(
+    params: &ScanParams,
+    exclude: &GlobSet,
     path: &Path,
     current_depth: usize,
     max_depth: usize,
-) -> anyhow::Result<(Vec<Entry>, Vec<PathBuf>)> {
+    found: &Mutex<Found>,
+) -> anyhow::Result<()> {
     log::trace!(
         "scanning {}... (depth: {current_depth})",
         path.as_os_str().to_string_lossy()
     );
 
-    let mut urls = vec![];
-    let mut irrelevant = vec![];
-
     match fs::read_dir(path) {
-        Ok(entries) => {
-            for d in entries.filter_map(|d| d.ok()) {
-                let path = d.path();
-                let path_string = path.as_os_str().to_string_lossy();
-
-                if !d.file_type()?.is_dir() {
-                    log::warn!("'{path_string}' is not a directory, skipping...");
-                    irrelevant.push(d.path());
-                    continue;
+        Ok(entries) => entries
+            .filter_map(|d| d.ok())
+            .collect::<Vec<fs::DirEntry>>()
+            .par_iter()
+            .try_for_each(|d| {
+                if found.lock().unwrap().is_full(params.limit) {
+                    return Ok(());
                 }
 
-                let url = match Repository::open(d.path()) {
-                    Ok(repo) => {
-                        log::trace!("found repository: {path_string}");
-                        let remotes = repo
-                            .remotes()?
-                            .iter()
-                            .flatten()
-                            .map(|r| r.to_owned())
-                            .collect::<Vec<String>>();
-
-                        let remote_name = if remotes.iter().any(|r| r == "origin") {
-                            "origin".to_owned()
-                        } else if let Some(r) = remotes.first() {
-                            r.clone()
-                        } else {
-                            log::error!("no remotes found for '{path_string}', skipping...");
-                            continue;
-                        };
-
-                        if let Some(url) = repo.find_remote(&remote_name)?.url() {
-                            url.to_owned()
-                        } else {
-                            log::error!(
-                        "no url found for remote '{remote_name}' at '{path_string}', skipping..."
-                    );
-                            continue;
-                        }
-                    }
-                    Err(e) => {
-                        if e.code() == git2::ErrorCode::NotFound {
-                            if current_depth < max_depth {
-                                log::trace!(
-                                    "'{path_string}' is not a git repository, recursing into it..."
-                                );
-
-                                let (mut u, mut i) = local(&path, current_depth + 1, max_depth)?;
-
-                                urls.append(&mut u);
-                                irrelevant.append(&mut i);
-                            } else {
-                                log::warn!("'{path_string}' is not a git repository");
-                            }
-                        } else {
-                            anyhow::bail!("failed to open repository: {path_string}: {e}");
-                        }
-
-                        continue;
-                    }
-                };
-
-                log::trace!("found repository remote: {path_string} ({url})");
-
-                urls.push(Entry {
-                    path: d.path(),
-                    remote_url: url,
-                });
-            }
-        }
+                local_entry(params, exclude, d, current_depth, max_depth, found)
+            }),
         Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
             log::error!(
                 "access denied to directory '{}', skipping...",
                 path.as_os_str().to_string_lossy()
             );
-            return Ok((vec![], vec![]));
+            Ok(())
         }
         Err(e) => {
             anyhow::bail!(
@@ -181,8 +771,393 @@ fn local(
             );
         }
     }
+}
+
+/// Handle a single directory entry, recursing into directories that aren't repositories
+fn local_entry(
+    params: &ScanParams,
+    exclude: &GlobSet,
+    d: &fs::DirEntry,
+    current_depth: usize,
+    max_depth: usize,
+    found: &Mutex<Found>,
+) -> anyhow::Result<()> {
+    let path = d.path();
+    let path_string = path.as_os_str().to_string_lossy();
+
+    if exclude.is_match(d.file_name()) || exclude.is_match(&path) {
+        log::trace!("'{path_string}' is excluded, skipping...");
+        return Ok(());
+    }
+
+    if !d.file_type()?.is_dir() {
+        if params.peek_archives {
+            match peek_archive(&path) {
+                Ok(true) => {
+                    log::trace!("found archived repository: {path_string}");
+                    found.lock().unwrap().archived.push(d.path());
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(e) => log::warn!("failed to peek into '{path_string}': {e}"),
+            }
+        }
+
+        log::warn!("'{path_string}' is not a directory, skipping...");
+        found.lock().unwrap().irrelevant.push(d.path());
+        return Ok(());
+    }
+
+    let repo = match Repository::open(d.path()) {
+        Ok(repo) => repo,
+        Err(e) => {
+            if e.code() == git2::ErrorCode::NotFound {
+                if current_depth < max_depth {
+                    log::trace!("'{path_string}' is not a git repository, recursing into it...");
+
+                    local(params, exclude, &path, current_depth + 1, max_depth, found)?;
+                } else {
+                    log::warn!("'{path_string}' is not a git repository");
+                }
+            } else {
+                anyhow::bail!("failed to open repository: {path_string}: {e}");
+            }
+
+            return Ok(());
+        }
+    };
+
+    log::trace!("found repository: {path_string}");
+    let remotes = repo
+        .remotes()?
+        .iter()
+        .flatten()
+        .map(|r| r.to_owned())
+        .collect::<Vec<String>>();
+
+    let remote_name = if let Some(r) = params.prefer_remote.iter().find(|p| remotes.contains(p)) {
+        r.clone()
+    } else if let Some(r) = remotes.first() {
+        r.clone()
+    } else {
+        log::error!("no remotes found for '{path_string}', skipping...");
+        return Ok(());
+    };
+
+    let remote = repo.find_remote(&remote_name)?;
+
+    let Some(url) = remote.url().map(|u| u.to_owned()) else {
+        log::error!("no url found for remote '{remote_name}' at '{path_string}', skipping...");
+        return Ok(());
+    };
+
+    let push_url = remote.pushurl().filter(|p| *p != url).map(|p| p.to_owned());
+
+    let mut all_remotes = vec![];
+
+    for name in &remotes {
+        match repo.find_remote(name)?.url() {
+            Some(u) => all_remotes.push((name.clone(), u.to_owned())),
+            None => log::warn!("no url found for remote '{name}' at '{path_string}'"),
+        }
+    }
+
+    if url.trim().is_empty() {
+        log::error!("empty url for remote of '{path_string}', skipping...");
+        found.lock().unwrap().invalid.push(d.path());
+        return Ok(());
+    }
+
+    log::trace!("found repository remote: {path_string} ({remote_name}: {url})");
+
+    if let Some(push_url) = &push_url {
+        log::trace!("found distinct push url: {path_string} ({push_url})");
+    }
+
+    let config = if params.dump_config {
+        match dump_config(&repo) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                log::warn!("failed to read config of '{path_string}': {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let head = match head_commit(&repo) {
+        Ok(c) => c,
+        Err(e) => {
+            log::warn!("failed to resolve HEAD of '{path_string}': {e}");
+            None
+        }
+    };
+
+    let last_commit_hash = head.as_ref().map(|c| c.id().to_string());
+    let last_commit_date = head
+        .as_ref()
+        .and_then(|c| DateTime::from_timestamp(c.time().seconds(), 0));
+    let last_repo_fetch = last_fetch(&repo);
+
+    let signing = match &head {
+        Some(commit) if params.print_signing => match signing(&repo, commit) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                log::warn!("failed to inspect HEAD of '{path_string}': {e}");
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let head_subject = head
+        .as_ref()
+        .filter(|_| params.print_subject)
+        .map(|c| subject(c, params.subject_length));
+
+    let dirty = if params.check_dirty && !repo.is_bare() {
+        match is_dirty(&repo) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                log::warn!("failed to read the status of '{path_string}': {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let branch = branch(&repo);
+    let size_bytes = params.with_size.then(|| size(&path));
+
+    let mut found = found.lock().unwrap();
+
+    // other threads may have reached the limit while this one was busy
+    if found.is_full(params.limit) {
+        return Ok(());
+    }
+
+    found.push(Entry {
+        path: d.path(),
+        remote_url: url,
+        remote_name,
+        push_url,
+        remotes: all_remotes,
+        last_commit_hash,
+        last_commit_date,
+        last_repo_fetch,
+        config,
+        signing,
+        head_subject,
+        dirty,
+        branch,
+        size_bytes,
+    })?;
+
+    Ok(())
+}
+
+/// The commit HEAD points to, `None` when HEAD is unborn
+fn head_commit(repo: &Repository) -> Result<Option<git2::Commit<'_>>, git2::Error> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?)),
+        Err(e)
+            if matches!(
+                e.code(),
+                git2::ErrorCode::UnbornBranch | git2::ErrorCode::NotFound
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Short name of the branch HEAD points to, read from HEAD itself so unborn branches and the
+/// default branch of bare repositories are reported too
+fn branch(repo: &Repository) -> Option<String> {
+    let head = repo.find_reference("HEAD").ok()?;
+
+    Some(match head.symbolic_target() {
+        Some(target) => target
+            .strip_prefix("refs/heads/")
+            .unwrap_or(target)
+            .to_owned(),
+        None => "(detached)".to_owned(),
+    })
+}
+
+/// When the repository was last fetched, going by the modification time of `FETCH_HEAD`
+fn last_fetch(repo: &Repository) -> Option<DateTime<Utc>> {
+    fs::metadata(repo.path().join("FETCH_HEAD"))
+        .and_then(|m| m.modified())
+        .ok()
+        .map(|t| DateTime::<Utc>::from(t).trunc_subsecs(0))
+}
+
+/// Signature status and identities of a commit
+fn signing(repo: &Repository, commit: &git2::Commit) -> Result<Signing, git2::Error> {
+    let head_signed = match repo.extract_signature(&commit.id(), None) {
+        Ok(_) => true,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => false,
+        Err(e) => return Err(e),
+    };
+
+    let head_author_email = commit.author().email().map(|e| e.to_owned());
+    let head_committer_email = commit.committer().email().map(|e| e.to_owned());
+
+    Ok(Signing {
+        head_signed,
+        head_author_email,
+        head_committer_email,
+    })
+}
+
+/// Sum of the file sizes under `path`, entries that can't be read are skipped
+fn size(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| match e {
+            Ok(e) => Some(e),
+            Err(e) => {
+                log::debug!("skipping while measuring '{}': {e}", path.display());
+                None
+            }
+        })
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Whether the working tree has modified, staged or untracked (but not ignored) files
+pub(crate) fn is_dirty(repo: &Repository) -> Result<bool, git2::Error> {
+    let statuses = repo.statuses(Some(
+        git2::StatusOptions::new()
+            .include_untracked(true)
+            .include_ignored(false),
+    ))?;
+
+    Ok(!statuses.is_empty())
+}
+
+/// First line of the commit message without control characters, truncated to `max_length` characters
+fn subject(commit: &git2::Commit, max_length: usize) -> String {
+    let subject = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+
+    if subject.chars().count() > max_length {
+        let mut truncated = subject
+            .chars()
+            .take(max_length.saturating_sub(3))
+            .collect::<String>();
+        truncated.push_str("...");
+        truncated
+    } else {
+        subject
+    }
+}
+
+/// Collect the remote, branch upstream and core keys from the repository's own config,
+/// keeping every value of multivars
+fn dump_config(repo: &Repository) -> Result<BTreeMap<String, Vec<String>>, git2::Error> {
+    let mut config = BTreeMap::new();
+
+    repo.config()?
+        .open_level(git2::ConfigLevel::Local)?
+        .entries(Some(r"^(remote\..*|branch\..*\.(remote|merge)|core\..*)$"))?
+        .for_each(|e| {
+            if let (Some(name), Some(value)) = (e.name(), e.value()) {
+                config
+                    .entry(name.to_owned())
+                    .or_insert_with(Vec::new)
+                    .push(value.to_owned());
+            }
+        })?;
+
+    Ok(config)
+}
+
+/// Check whether a tar or zip archive (by extension) contains a `.git` directory at its root,
+/// or directly under a single top-level directory, without extracting it
+fn peek_archive(path: &Path) -> anyhow::Result<bool> {
+    let name = path.as_os_str().to_string_lossy().to_lowercase();
+
+    if name.ends_with(".zip") {
+        let archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+        return Ok(contains_repository(archive.file_names().map(PathBuf::from)));
+    }
+
+    let reader: Box<dyn io::Read> = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Box::new(flate2::read::GzDecoder::new(fs::File::open(path)?))
+    } else if name.ends_with(".tar") {
+        Box::new(fs::File::open(path)?)
+    } else {
+        return Ok(false);
+    };
+
+    let paths = tar::Archive::new(reader)
+        .entries()?
+        .map(|e| Ok(e?.path()?.into_owned()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+
+    Ok(contains_repository(paths))
+}
+
+/// Whether the listed archive paths have `.git` at the root, or under the only top-level directory
+fn contains_repository(paths: impl IntoIterator<Item = PathBuf>) -> bool {
+    let mut top_level = HashSet::new();
+    let mut nested = false;
+
+    for p in paths {
+        let mut components = p
+            .components()
+            .filter(|c| !matches!(c, std::path::Component::CurDir))
+            .map(|c| c.as_os_str().to_owned());
+
+        let Some(first) = components.next() else {
+            continue;
+        };
+
+        if first == ".git" {
+            return true;
+        }
+
+        if components.next().is_some_and(|c| c == ".git") {
+            nested = true;
+        }
+
+        top_level.insert(first);
+    }
 
-    Ok((urls, irrelevant))
+    nested && top_level.len() == 1
+}
+
+/// Keep the first entry of each canonical remote url, so with sorted entries the one with the
+/// smallest path
+fn dedupe(entries: &mut Vec<Entry>) {
+    let mut kept: HashMap<String, PathBuf> = HashMap::new();
+
+    entries.retain(
+        |e| match kept.entry(crate::url::canonical_url(&e.remote_url)) {
+            hash_map::Entry::Occupied(k) => {
+                log::info!(
+                    "collapsing '{}' into '{}' ({})",
+                    e.path.display(),
+                    k.get().display(),
+                    e.remote_url
+                );
+                false
+            }
+            hash_map::Entry::Vacant(v) => {
+                v.insert(e.path.clone());
+                true
+            }
+        },
+    );
 }
 
 fn find_duplicates(entries: &[Entry]) -> Vec<Entry> {
@@ -196,8 +1171,141 @@ fn find_duplicates(entries: &[Entry]) -> Vec<Entry> {
     }
 
     occurrences
-        .into_iter()
-        .filter_map(|(_, v)| if v.len() > 1 { Some(v) } else { None })
+        .into_values()
+        .filter_map(|v| if v.len() > 1 { Some(v) } else { None })
         .flatten()
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        params: ScanParams,
+    }
+
+    fn init(path: &Path, url: &str) {
+        Repository::init(path)
+            .unwrap()
+            .remote("origin", url)
+            .unwrap();
+    }
+
+    fn scan_to(directory: &Path, output: &Path, args: &[&str]) {
+        let mut argv = vec![
+            "scan",
+            "-d",
+            directory.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ];
+        argv.extend(args);
+
+        scan(Cli::parse_from(argv).params).unwrap();
+    }
+
+    #[test]
+    fn append_keeps_each_url_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let repos = dir.path().join("repos");
+        for name in ["a", "b", "c", "d"] {
+            init(
+                &repos.join(name),
+                &format!("https://github.com/o/{name}.git"),
+            );
+        }
+        let output = dir.path().join("out.txt");
+
+        scan_to(&repos, &output, &[]);
+        init(&repos.join("e"), "https://github.com/o/e.git");
+        scan_to(&repos, &output, &["--append"]);
+
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "https://github.com/o/a.git\n\
+             https://github.com/o/b.git\n\
+             https://github.com/o/c.git\n\
+             https://github.com/o/d.git\n\
+             https://github.com/o/e.git\n"
+        );
+    }
+
+    #[test]
+    fn dedupe_rewrites_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let repos = dir.path().join("repos");
+        init(&repos.join("a"), "https://github.com/o/a.git");
+        init(&repos.join("b"), "git@github.com:o/a");
+        init(&repos.join("c"), "https://github.com/o/c.git");
+        init(&repos.join("d"), "https://github.com/o/d.git");
+        let output = dir.path().join("out.txt");
+
+        scan_to(&repos, &output, &["--dedupe"]);
+
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "https://github.com/o/a.git\n\
+             https://github.com/o/c.git\n\
+             https://github.com/o/d.git\n"
+        );
+    }
+
+    #[test]
+    fn dedupe_keeps_smallest_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let repos = dir.path().join("repos");
+        init(&repos.join("a"), "git@github.com:o/r");
+        init(&repos.join("b"), "https://github.com/o/r.git");
+        let output = dir.path().join("out.csv");
+
+        scan_to(&repos, &output, &["--dedupe", "--format", "csv"]);
+
+        let rows = read_rows(&output).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].path, Path::new("a"));
+        assert_eq!(rows[0].remote_url, "git@github.com:o/r");
+    }
+
+    #[test]
+    fn archive_repository_layouts() {
+        let paths = |p: &[&str]| p.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert!(contains_repository(paths(&[".git/", ".git/HEAD", "src/"])));
+        assert!(contains_repository(paths(&[
+            "./",
+            "./.git/",
+            "./.git/HEAD"
+        ])));
+        assert!(contains_repository(paths(&[
+            "top/",
+            "top/.git/",
+            "top/src/"
+        ])));
+        assert!(!contains_repository(paths(&["a/", "a/.git/", "b/"])));
+    }
+
+    #[test]
+    fn depth_zero_finds_top_level_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path().join("repo")).unwrap();
+        repo.remote("origin", "https://github.com/o/r.git").unwrap();
+
+        let directory = dir.path().to_str().unwrap();
+        let params = Cli::parse_from(["scan", "-d", directory, "--depth", "0"]).params;
+        let found = Mutex::new(Found::default());
+
+        local_root(&params, &GlobSet::empty(), &found).unwrap();
+
+        let found = found.into_inner().unwrap();
+        assert_eq!(found.repositories.len(), 1);
+        assert_eq!(found.repositories[0].path, dir.path().join("repo"));
+        assert_eq!(
+            found.repositories[0].remote_url,
+            "https://github.com/o/r.git"
+        );
+    }
+}